@@ -1,11 +1,27 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
 use std::path::PathBuf;
 use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::thread::JoinHandle;
 use std::time::Duration;
+use std::time::Instant;
+use std::time::UNIX_EPOCH;
 
 use super::MediaScanner;
 
 use database::get_conn;
 use database::library::Library;
+use database::library::LibraryFilter;
 use database::library::MediaType;
 use database::media::Media;
 use database::mediafile::MediaFile;
@@ -22,33 +38,524 @@ use notify::RecursiveMode;
 use notify::Result as nResult;
 use notify::Watcher;
 
+use crossbeam_channel::select;
+use crossbeam_channel::Receiver as CrossbeamReceiver;
+use crossbeam_channel::Sender as CrossbeamSender;
+
+/// On-disk stat we keep next to each `MediaFile` row so a reconciliation pass can tell whether a
+/// file changed without re-probing it.
+struct FileStat {
+    size: u64,
+    mtime: u64,
+}
+
+fn stat_file(path: &Path) -> Option<FileStat> {
+    let meta = fs::metadata(path).ok()?;
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    Some(FileStat {
+        size: meta.len(),
+        mtime,
+    })
+}
+
+/// Whether `stat` still matches the size/mtime last persisted on a `MediaFile`. Shared by
+/// `reconcile` (deciding whether a known file needs re-probing) and `handle_write` (deciding
+/// whether a `Write` event actually changed anything), split out as a plain function so the gating
+/// itself can be tested without a `MediaFile`/DB round trip.
+fn stat_unchanged(file_size: Option<i64>, file_mtime: Option<i64>, stat: &FileStat) -> bool {
+    file_size == Some(stat.size as i64) && file_mtime == Some(stat.mtime as i64)
+}
+
+/// Stable identity for a `MediaFile`'s content, used to recognize a move regardless of what
+/// shape the underlying `notify` events take. We deliberately hash content rather than trust the
+/// OS file-id: the Remove+Create shape this is meant to catch is exactly what a cross-filesystem
+/// move produces, and a copy onto a different filesystem gets a brand new inode, so an
+/// inode-based identity would never match in the one case that needs it. Same-filesystem renames
+/// already arrive as a single `Rename` event and never go through this path at all.
+pub type Fingerprint = i64;
+
+const FINGERPRINT_SAMPLE_BYTES: u64 = 64 * 1024;
+
+/// How long a departing mediafile's identity stays in the pending-removal map waiting for a
+/// matching `Create` before we give up and treat it as a genuine deletion.
+const PENDING_REMOVAL_TTL: Duration = Duration::from_secs(5);
+
+/// A mediafile that was removed from disk, held back from deletion for `PENDING_REMOVAL_TTL` in
+/// case its fingerprint reappears under a new path.
+pub struct PendingRemoval {
+    media_file: MediaFile,
+    queued_at: Instant,
+}
+
+/// Hash the first and last `FINGERPRINT_SAMPLE_BYTES` of `path` plus its total size. This is the
+/// identity stored on `MediaFile` and compared on both sides of a move.
+fn compute_fingerprint(path: &Path) -> Option<Fingerprint> {
+    let mut file = fs::File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+
+    let mut hasher = DefaultHasher::new();
+    len.hash(&mut hasher);
+
+    let sample_len = FINGERPRINT_SAMPLE_BYTES.min(len) as usize;
+    let mut buf = vec![0u8; sample_len];
+
+    file.read_exact(&mut buf).ok()?;
+    buf.hash(&mut hasher);
+
+    if len > FINGERPRINT_SAMPLE_BYTES {
+        file.seek(SeekFrom::End(-(sample_len as i64))).ok()?;
+        file.read_exact(&mut buf).ok()?;
+        buf.hash(&mut hasher);
+    }
+
+    Some(hasher.finish() as Fingerprint)
+}
+
+/// How long a burst of events on the same path is allowed to build up before we drain and apply
+/// it. Long enough to swallow an editor's atomic-save dance or a bulk import's flurry of writes,
+/// short enough that a lone event still gets handled promptly.
+const COALESCE_WINDOW: Duration = Duration::from_millis(250);
+
+/// How many consecutive samples of an unchanged file size we require before we consider a
+/// freshly created file done being written to.
+const SETTLE_STABLE_SAMPLES: u32 = 2;
+
+/// Backoff bounds for polling a settling file's size: start quick for small files that finish
+/// fast, but back off so a multi-gigabyte copy isn't polled needlessly often.
+const SETTLE_INITIAL_DELAY: Duration = Duration::from_millis(200);
+const SETTLE_MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// Give up waiting for a file to stabilize after this long and mount it anyway, so a file that's
+/// simply never going to stop growing (e.g. a live-appended log-like file) doesn't get stuck
+/// unmounted forever.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, PartialEq, Eq)]
+enum SettleOutcome {
+    Settled,
+    Gone,
+    TimedOut,
+}
+
+/// Poll `path`'s size with exponential backoff until it's been stable across
+/// `SETTLE_STABLE_SAMPLES` consecutive samples, the file disappears (an aborted download), or
+/// `SETTLE_TIMEOUT` elapses.
+fn wait_for_settled_file(path: &Path) -> SettleOutcome {
+    let start = Instant::now();
+    let mut delay = SETTLE_INITIAL_DELAY;
+    let mut last_size = None;
+    let mut stable_samples = 0;
+
+    loop {
+        let size = match fs::metadata(path) {
+            Ok(meta) => meta.len(),
+            Err(_) => return SettleOutcome::Gone,
+        };
+
+        if last_size == Some(size) {
+            stable_samples += 1;
+
+            if stable_samples >= SETTLE_STABLE_SAMPLES {
+                return SettleOutcome::Settled;
+            }
+        } else {
+            last_size = Some(size);
+            stable_samples = 0;
+        }
+
+        if start.elapsed() >= SETTLE_TIMEOUT {
+            return SettleOutcome::TimedOut;
+        }
+
+        thread::sleep(delay);
+        delay = (delay * 2).min(SETTLE_MAX_DELAY);
+    }
+}
+
+/// The effective, de-duplicated outcome of however many raw `notify` events landed on a path
+/// within one `COALESCE_WINDOW`.
+enum PendingEvent {
+    Create,
+    Write,
+    Remove,
+    Rename { from: PathBuf },
+}
+
+/// Fold one more raw event into the coalesced view for its path, applying the collapsing rules
+/// described on the request: a create absorbs any writes that follow it, and a create immediately
+/// undone by a remove cancels out to nothing.
+fn record_pending_event(pending: &mut HashMap<PathBuf, PendingEvent>, event: DebouncedEvent) {
+    match event {
+        DebouncedEvent::Create(path) => {
+            pending.insert(path, PendingEvent::Create);
+        }
+        DebouncedEvent::Write(path) => {
+            pending.entry(path).or_insert(PendingEvent::Write);
+        }
+        DebouncedEvent::Remove(path) => match pending.remove(&path) {
+            Some(PendingEvent::Create) => {
+                // A file that was created and removed again within the same window never needs
+                // to be looked at at all.
+            }
+            _ => {
+                pending.insert(path, PendingEvent::Remove);
+            }
+        },
+        DebouncedEvent::Rename(from, to) => {
+            pending.remove(&from);
+            pending.insert(to, PendingEvent::Rename { from });
+        }
+        _ => {}
+    }
+}
+
+/// A running `ScannerDaemon` watch loop. Dropping this without calling `shutdown` disconnects the
+/// shutdown channel, which the watch loop also treats as a stop signal, but leaves its thread
+/// detached; call `shutdown` then `join` to wait for a clean stop.
+pub struct DaemonHandle {
+    shutdown_tx: CrossbeamSender<()>,
+    worker: JoinHandle<()>,
+}
+
+impl DaemonHandle {
+    /// Signal the watch loop to stop processing events and return.
+    pub fn shutdown(&self) {
+        let _ = self.shutdown_tx.send(());
+    }
+
+    /// Block until the watch loop thread has exited.
+    pub fn join(self) {
+        let _ = self.worker.join();
+    }
+}
+
 pub trait ScannerDaemon: MediaScanner {
-    fn start_daemon(&self) -> nResult<()> {
-        let (tx, rx) = mpsc::channel();
-        let mut watcher = <RecommendedWatcher as Watcher>::new(tx, Duration::from_secs(1))?;
-        let log = self.logger_ref();
+    /// Storage for mediafiles that were just removed from disk, keyed by content fingerprint, so
+    /// a matching `Create` can be recognized as a move instead of a delete+re-mount.
+    fn pending_removals_ref(&self) -> &Mutex<HashMap<Fingerprint, PendingRemoval>>;
+
+    /// Start watching `self.library_ref().location` on a background thread and return a handle
+    /// the caller can use to shut the watch loop down and join it. `notify`'s watcher only
+    /// speaks `std::sync::mpsc`, so its events are bridged onto a `crossbeam_channel` that the
+    /// watch loop can `select!` over alongside its shutdown signal.
+    ///
+    /// Breaking change: this used to take `&self` and never return. Existing callers need to
+    /// hold the daemon behind an `Arc` and call `Arc::new(daemon).start_daemon()?`, keeping the
+    /// returned `DaemonHandle` around to `shutdown()`/`join()` it later.
+    fn start_daemon(self: Arc<Self>) -> nResult<DaemonHandle>
+    where
+        Self: Send + Sync + 'static,
+    {
+        let (notify_tx, notify_rx) = mpsc::channel();
+        let mut watcher = <RecommendedWatcher as Watcher>::new(notify_tx, Duration::from_secs(1))?;
 
         watcher.watch(
             self.library_ref().location.as_str(),
             RecursiveMode::Recursive,
         )?;
 
+        self.reconcile();
+
+        let (events_tx, events_rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            while let Ok(event) = notify_rx.recv() {
+                if events_tx.send(event).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let (shutdown_tx, shutdown_rx) = crossbeam_channel::bounded(0);
+
+        let daemon = self;
+        let worker = thread::spawn(move || daemon.run_event_loop(events_rx, shutdown_rx, watcher));
+
+        Ok(DaemonHandle {
+            shutdown_tx,
+            worker,
+        })
+    }
+
+    /// The watch loop proper: drain coalesced events on a tick, expire stale pending removals on
+    /// that same tick, and stop as soon as a shutdown signal arrives. `watcher` is held here
+    /// purely to keep it (and the OS watch it owns) alive for the lifetime of the loop. Takes
+    /// `self` by `Arc` (rather than `&self`) so a `Create` can hand a clone off to its own
+    /// settling thread without blocking this loop.
+    fn run_event_loop(
+        self: Arc<Self>,
+        events_rx: CrossbeamReceiver<DebouncedEvent>,
+        shutdown_rx: CrossbeamReceiver<()>,
+        watcher: RecommendedWatcher,
+    ) where
+        Self: Send + Sync + 'static,
+    {
+        let log = self.logger_ref();
+        let ticker = crossbeam_channel::tick(COALESCE_WINDOW);
+        let mut pending: HashMap<PathBuf, PendingEvent> = HashMap::new();
+
         loop {
-            match rx.recv() {
-                Ok(DebouncedEvent::Create(path)) => self.handle_create(path),
-                Ok(DebouncedEvent::Rename(from, to)) => self.handle_rename(from, to),
-                Ok(DebouncedEvent::Remove(path)) => self.handle_remove(path),
-                Ok(event) => debug!(log, "Tried to handle unmatched event {:?}", event),
-                Err(e) => error!(log, "Received error: {:?}", e),
+            select! {
+                recv(events_rx) -> event => match event {
+                    Ok(event) => record_pending_event(&mut pending, event),
+                    Err(_) => break,
+                },
+                recv(ticker) -> _ => {
+                    if !pending.is_empty() {
+                        Arc::clone(&self).flush_coalesced(&mut pending);
+                    }
+                    self.sweep_pending_removals();
+                },
+                recv(shutdown_rx) -> _ => {
+                    debug!(log, "Scanner daemon received shutdown signal, stopping watch loop");
+                    break;
+                },
+            }
+        }
+
+        if !pending.is_empty() {
+            Arc::clone(&self).flush_coalesced(&mut pending);
+        }
+
+        drop(watcher);
+    }
+
+    /// Apply every coalesced event accumulated since the last flush and clear the map. `Create`
+    /// is handed off to a dedicated settling thread (see `handle_create`) so a large in-progress
+    /// copy can't stall this loop; the rest are cheap enough to run inline.
+    ///
+    /// `Remove`s are applied before everything else: a cross-directory move surfaces as a
+    /// `Remove` on the old path and a `Create` on the new one, and `handle_create` only recognizes
+    /// it as a move if the old path's fingerprint has already been stashed by `handle_remove`. A
+    /// plain `pending.drain()` hands them out in arbitrary `HashMap` order, so without this split
+    /// the move would only be recognized when `Remove` happened to drain first.
+    fn flush_coalesced(self: Arc<Self>, pending: &mut HashMap<PathBuf, PendingEvent>)
+    where
+        Self: Send + Sync + 'static,
+    {
+        let mut removals = Vec::new();
+        let mut rest = Vec::new();
+
+        for (path, event) in pending.drain() {
+            match event {
+                PendingEvent::Remove => removals.push(path),
+                other => rest.push((path, other)),
+            }
+        }
+
+        for path in removals {
+            self.handle_remove(path);
+        }
+
+        for (path, event) in rest {
+            match event {
+                PendingEvent::Create => Arc::clone(&self).handle_create(path),
+                PendingEvent::Rename { from } => self.handle_rename(from, path),
+                PendingEvent::Write => self.handle_write(path),
+                PendingEvent::Remove => unreachable!("removals were drained separately above"),
+            }
+        }
+    }
+
+    /// Walk the library location once and bring the DB back in sync with whatever happened to
+    /// the files on disk while the daemon wasn't running to see it.
+    ///
+    /// For every file the DB already knows about we compare the persisted size/mtime against the
+    /// file's current stat: unchanged means clean, changed means it needs a re-probe, and a
+    /// missing DB row for a file that exists on disk means it needs to be mounted. DB rows whose
+    /// file no longer exists on disk get the same ghost-media cleanup as `handle_remove`.
+    fn reconcile(&self) {
+        let log = self.logger_ref();
+        let conn = self.conn_ref();
+
+        debug!(log, "Starting reconciliation pass for {:?}", self.library_ref().location);
+
+        let mut seen_on_disk = Vec::new();
+        walk_supported_files::<Self>(
+            &PathBuf::from(self.library_ref().location.as_str()),
+            &self.library_ref().filters,
+            &mut seen_on_disk,
+        );
+
+        for path in &seen_on_disk {
+            let path_str = match path.to_str() {
+                Some(x) => x,
+                None => continue,
+            };
+
+            match MediaFile::get_by_file(conn, path_str) {
+                Ok(media_file) => {
+                    let stat = match stat_file(path) {
+                        Some(x) => x,
+                        None => continue,
+                    };
+
+                    let clean = stat_unchanged(media_file.file_size, media_file.file_mtime, &stat);
+
+                    if !clean {
+                        debug!(log, "Reconcile: {:?} changed on disk, re-probing", path);
+                        self.reprobe(path.clone(), &media_file, stat);
+                    }
+                }
+                Err(_) => {
+                    debug!(log, "Reconcile: {:?} is new, mounting", path);
+                    if let Err(e) = self.mount_file(path.clone()) {
+                        warn!(log, "Failed to mount file={:?} e={:?}", path, e);
+                    } else {
+                        self.persist_mount_stat(path);
+                    }
+                }
+            }
+        }
+
+        // Scoped to this library's own rows: `get_all` would also catch rows belonging to other
+        // libraries, which don't appear under `seen_on_disk` and would look "missing" here even
+        // though they're perfectly fine.
+        if let Ok(known_files) = MediaFile::get_of_library(conn, self.library_ref().id) {
+            for media_file in known_files {
+                let still_present = seen_on_disk.iter().any(|p| p.to_str() == Some(media_file.target_file.as_str()));
+
+                if still_present {
+                    continue;
+                }
+
+                // `seen_on_disk` only holds files the filters still allow, so a row missing from
+                // it could mean the file is gone, or it could just have been excluded by a filter
+                // change since it was mounted. Only the former is a real deletion.
+                if Path::new(media_file.target_file.as_str()).exists() {
+                    debug!(
+                        log,
+                        "Reconcile: {:?} still on disk but now excluded by filters, leaving it alone",
+                        media_file.target_file
+                    );
+                    continue;
+                }
+
+                debug!(log, "Reconcile: {:?} no longer on disk, cleaning up", media_file.target_file);
+                // Delete directly rather than going through `handle_remove`: that path defers
+                // to the pending-removal map so a matching `Create` can still arrive, but a
+                // reconcile pass already has the full, final picture of what's on disk, so
+                // there's nothing left to wait for.
+                self.finalize_removal(media_file);
+            }
+        }
+
+        self.fix_orphans();
+    }
+
+    /// After a successful `mount_file`, look up the row it created and persist its on-disk size,
+    /// mtime, and content fingerprint, so a later `reconcile` pass can trust the stat without
+    /// re-probing and a later `handle_remove` has an identity to match a move against.
+    fn persist_mount_stat(&self, path: &Path) {
+        let log = self.logger_ref();
+
+        let media_file = match path
+            .to_str()
+            .and_then(|x| MediaFile::get_by_file(self.conn_ref(), x).ok())
+        {
+            Some(x) => x,
+            None => return,
+        };
+
+        let stat = match stat_file(path) {
+            Some(x) => x,
+            None => return,
+        };
+
+        let update_query = UpdateMediaFile {
+            file_size: Some(stat.size as i64),
+            file_mtime: Some(stat.mtime as i64),
+            fingerprint: compute_fingerprint(path),
+            ..Default::default()
+        };
+
+        if let Err(e) = update_query.update(self.conn_ref(), media_file.id) {
+            error!(log, "Failed to persist mount stat for {:?} e={:?}", path, e);
+        }
+    }
+
+    /// Re-run the media probe for a file whose on-disk state diverged from what we persisted, and
+    /// push the refreshed fields through `UpdateMediaFile` without touching the parent `Media`
+    /// association or the mediafile's id. Also recomputes the fingerprint: an in-place
+    /// re-encode/remux changes the content a move would need to match against, so the stored one
+    /// would otherwise go stale.
+    fn reprobe(&self, path: PathBuf, media_file: &MediaFile, stat: FileStat) {
+        let log = self.logger_ref();
+
+        let probe = match self.probe_file(&path) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(log, "Failed to re-probe file={:?} e={:?}", path, e);
+                return;
             }
+        };
+
+        let update_query = UpdateMediaFile {
+            file_size: Some(stat.size as i64),
+            file_mtime: Some(stat.mtime as i64),
+            fingerprint: compute_fingerprint(&path),
+            ..probe.into_update()
+        };
+
+        if let Err(e) = update_query.update(self.conn_ref(), media_file.id) {
+            error!(log, "Failed to persist re-probe for {:?} e={:?}", path, e);
+        }
+    }
+
+    /// An existing file was modified in place (re-encoded, remuxed, tags edited, ...). If its
+    /// size or mtime actually moved since we last probed it, re-probe and persist the refreshed
+    /// fields; a `Write` event that leaves both size and mtime as last persisted is a no-op.
+    fn handle_write(&self, path: PathBuf) {
+        let log = self.logger_ref();
+        let conn = self.conn_ref();
+
+        debug!(log, "Received handle_write event type: {:?}", path);
+
+        let media_file = match path
+            .to_str()
+            .and_then(|x| MediaFile::get_by_file(conn, x).ok())
+        {
+            Some(x) => x,
+            None => return,
+        };
+
+        let stat = match stat_file(&path) {
+            Some(x) => x,
+            None => return,
+        };
+
+        if stat_unchanged(media_file.file_size, media_file.file_mtime, &stat) {
+            return;
         }
+
+        self.reprobe(path, &media_file, stat);
     }
 
-    fn handle_create(&self, path: PathBuf) {
+    /// Handle a (coalesced) `Create`. The cheap checks — filter, fingerprint-match-as-move — run
+    /// inline; mounting a genuinely new file is deferred to a dedicated settling thread (see
+    /// `settle_and_mount`) so a large in-progress copy can't stall the watch loop or delay it
+    /// noticing a shutdown signal. A newly created directory is walked with our own filtered
+    /// walker and each discovered file re-enters this same function, so excluded paths are never
+    /// mounted or descended into there either. Takes `self` by `Arc` purely so that thread (and
+    /// the recursive calls below) can keep a reference to the daemon after this call returns.
+    fn handle_create(self: Arc<Self>, path: PathBuf)
+    where
+        Self: Send + Sync + 'static,
+    {
         let log = self.logger_ref();
 
         debug!(log, "Received handle_create event type: {:?}", path);
 
+        if !self.library_ref().filters.is_allowed(&path) {
+            debug!(log, "Filter rejected {:?}, ignoring", path);
+            return;
+        }
+
         if path.is_file()
             && path
                 .extension()
@@ -57,14 +564,85 @@ pub trait ScannerDaemon: MediaScanner {
                     <Self as MediaScanner>::SUPPORTED_EXTS.contains(&e)
                 })
         {
-            if let Err(e) = self.mount_file(path.clone()) {
-                warn!(log, "Failed to mount file={:?} e={:?}", path, e);
+            self.sweep_pending_removals();
+
+            let moved = compute_fingerprint(&path).and_then(|fingerprint| {
+                self.pending_removals_ref()
+                    .lock()
+                    .unwrap()
+                    .remove(&fingerprint)
+            });
+
+            if let Some(pending) = moved {
+                debug!(
+                    log,
+                    "Fingerprint match: treating create of {:?} as a move of mediafile_id={}",
+                    path,
+                    pending.media_file.id
+                );
+
+                let update_query = UpdateMediaFile {
+                    target_file: path.to_str().map(|x| x.to_string()),
+                    ..Default::default()
+                };
+
+                if let Err(e) = update_query.update(self.conn_ref(), pending.media_file.id) {
+                    error!(
+                        log,
+                        "Failed to update target_file for moved mediafile_id={} e={:?}",
+                        pending.media_file.id,
+                        e
+                    );
+                }
+
+                self.fix_orphans();
                 return;
             }
+
+            thread::spawn(move || self.settle_and_mount(path));
+            return;
         } else if path.is_dir() {
-            self.start(path.to_str());
+            // Walk the new directory with our own filtered walker rather than delegating to
+            // `MediaScanner::start`, which knows nothing about per-library filters: descending
+            // into it unfiltered would mount (or recurse into) exactly the excluded paths this
+            // guard exists to keep out.
+            let mut new_files = Vec::new();
+            walk_supported_files::<Self>(&path, &self.library_ref().filters, &mut new_files);
+
+            for file in new_files {
+                Arc::clone(&self).handle_create(file);
+            }
+        }
+
+        self.fix_orphans();
+    }
+
+    /// Poll a freshly created file until its size stabilizes (or it disappears, or the settle
+    /// timeout elapses), then mount it. Runs on its own thread, off the watch loop, so settling a
+    /// large copy never blocks other events or a pending shutdown from being processed.
+    fn settle_and_mount(&self, path: PathBuf) {
+        let log = self.logger_ref();
+
+        match wait_for_settled_file(&path) {
+            SettleOutcome::Gone => {
+                debug!(log, "{:?} disappeared while settling, dropping", path);
+                return;
+            }
+            SettleOutcome::TimedOut => {
+                warn!(
+                    log,
+                    "{:?} never stabilized within the settle timeout, mounting anyway", path
+                );
+            }
+            SettleOutcome::Settled => {}
+        }
+
+        if let Err(e) = self.mount_file(path.clone()) {
+            warn!(log, "Failed to mount file={:?} e={:?}", path, e);
+            return;
         }
 
+        self.persist_mount_stat(&path);
         self.fix_orphans();
     }
 
@@ -74,26 +652,78 @@ pub trait ScannerDaemon: MediaScanner {
 
         debug!(log, "Received handle remove {:?}", path);
 
-        if let Some(media_file) = path
+        let media_file = match path
             .to_str()
             .and_then(|x| MediaFile::get_by_file(conn, x).ok())
         {
-            let media = Media::get_of_mediafile(conn, &media_file);
+            Some(x) => x,
+            None => return,
+        };
 
-            if let Err(e) = MediaFile::delete(conn, media_file.id) {
-                error!(log, "Failed to remove mediafile because e={:?}", e);
-                return;
+        self.sweep_pending_removals();
+
+        match media_file.fingerprint {
+            Some(fingerprint) => {
+                self.pending_removals_ref().lock().unwrap().insert(
+                    fingerprint,
+                    PendingRemoval {
+                        media_file,
+                        queued_at: Instant::now(),
+                    },
+                );
             }
+            // No fingerprint on record (e.g. a row from before this feature existed) means we
+            // have nothing to match a later create against, so there's nothing to gain by
+            // holding it back.
+            None => self.finalize_removal(media_file),
+        }
+    }
 
-            // if we have a media with no mediafiles we want to purge it as it is a ghost media
-            // entry.
-            if let Ok(media) = media {
-                if let Ok(media_files) = MediaFile::get_of_media(conn, &media) {
-                    if media_files.is_empty() {
-                        if let Err(e) = Media::delete(conn, media.id) {
-                            error!(log, "Failed to delete ghost media {:?}", e);
-                            return;
-                        }
+    /// Delete expired entries out of the pending-removal map and actually remove them from the
+    /// DB, running the same ghost-media cleanup `handle_remove` always used to do inline. Called
+    /// on every watch-loop tick so a deletion with no follow-up `Create` still expires on its own;
+    /// also called from `handle_create`/`handle_remove` so an event doesn't have to wait for the
+    /// next tick to see an already-expired entry.
+    fn sweep_pending_removals(&self) {
+        let now = Instant::now();
+
+        let expired: Vec<PendingRemoval> = {
+            let mut pending = self.pending_removals_ref().lock().unwrap();
+            let expired_keys: Vec<Fingerprint> = pending
+                .iter()
+                .filter(|(_, entry)| now.duration_since(entry.queued_at) > PENDING_REMOVAL_TTL)
+                .map(|(fingerprint, _)| *fingerprint)
+                .collect();
+
+            expired_keys
+                .into_iter()
+                .filter_map(|fingerprint| pending.remove(&fingerprint))
+                .collect()
+        };
+
+        for entry in expired {
+            self.finalize_removal(entry.media_file);
+        }
+    }
+
+    fn finalize_removal(&self, media_file: MediaFile) {
+        let log = self.logger_ref();
+        let conn = self.conn_ref();
+
+        let media = Media::get_of_mediafile(conn, &media_file);
+
+        if let Err(e) = MediaFile::delete(conn, media_file.id) {
+            error!(log, "Failed to remove mediafile because e={:?}", e);
+            return;
+        }
+
+        // if we have a media with no mediafiles we want to purge it as it is a ghost media
+        // entry.
+        if let Ok(media) = media {
+            if let Ok(media_files) = MediaFile::get_of_media(conn, &media) {
+                if media_files.is_empty() {
+                    if let Err(e) = Media::delete(conn, media.id) {
+                        error!(log, "Failed to delete ghost media {:?}", e);
                     }
                 }
             }
@@ -127,3 +757,176 @@ pub trait ScannerDaemon: MediaScanner {
         }
     }
 }
+
+/// Recursively collect every file under `dir` whose extension is supported by `S`, for use by
+/// the reconciliation pass. `filter` is consulted for every path so excluded files are skipped
+/// and excluded directories are never even recursed into. Directories that can't be read
+/// (permissions, races with concurrent deletes) are skipped rather than aborting the whole walk.
+fn walk_supported_files<S: MediaScanner + ?Sized>(
+    dir: &Path,
+    filter: &LibraryFilter,
+    out: &mut Vec<PathBuf>,
+) {
+    let entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+
+        if !filter.is_allowed(&path) {
+            continue;
+        }
+
+        if path.is_dir() {
+            walk_supported_files::<S>(&path, filter, out);
+        } else if path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map_or(false, |e| S::SUPPORTED_EXTS.contains(&e))
+        {
+            out.push(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+    use std::sync::atomic::Ordering;
+
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    fn temp_path(name: &str) -> PathBuf {
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!(
+            "dim-scanner-daemon-test-{}-{}-{}",
+            std::process::id(),
+            n,
+            name
+        ))
+    }
+
+    #[test]
+    fn stat_unchanged_is_true_when_size_and_mtime_both_match() {
+        let stat = FileStat { size: 42, mtime: 1000 };
+        assert!(stat_unchanged(Some(42), Some(1000), &stat));
+    }
+
+    #[test]
+    fn stat_unchanged_is_false_when_size_differs() {
+        let stat = FileStat { size: 42, mtime: 1000 };
+        assert!(!stat_unchanged(Some(7), Some(1000), &stat));
+    }
+
+    #[test]
+    fn stat_unchanged_is_false_when_mtime_differs() {
+        let stat = FileStat { size: 42, mtime: 1000 };
+        assert!(!stat_unchanged(Some(42), Some(1), &stat));
+    }
+
+    #[test]
+    fn stat_unchanged_is_false_when_nothing_was_ever_persisted() {
+        let stat = FileStat { size: 42, mtime: 1000 };
+        assert!(!stat_unchanged(None, None, &stat));
+    }
+
+    #[test]
+    fn fingerprint_matches_identical_content_at_different_paths() {
+        let a = temp_path("a");
+        let b = temp_path("b");
+        fs::write(&a, b"the quick brown fox jumps over the lazy dog").unwrap();
+        fs::write(&b, b"the quick brown fox jumps over the lazy dog").unwrap();
+
+        assert_eq!(compute_fingerprint(&a), compute_fingerprint(&b));
+
+        fs::remove_file(&a).unwrap();
+        fs::remove_file(&b).unwrap();
+    }
+
+    #[test]
+    fn fingerprint_differs_for_different_content() {
+        let a = temp_path("c");
+        let b = temp_path("d");
+        fs::write(&a, b"content one").unwrap();
+        fs::write(&b, b"content two").unwrap();
+
+        assert_ne!(compute_fingerprint(&a), compute_fingerprint(&b));
+
+        fs::remove_file(&a).unwrap();
+        fs::remove_file(&b).unwrap();
+    }
+
+    #[test]
+    fn fingerprint_is_none_for_missing_file() {
+        let missing = temp_path("missing");
+        assert_eq!(compute_fingerprint(&missing), None);
+    }
+
+    #[test]
+    fn settled_file_reports_settled_once_size_is_stable() {
+        let path = temp_path("settled");
+        fs::write(&path, b"stable content").unwrap();
+
+        assert_eq!(wait_for_settled_file(&path), SettleOutcome::Settled);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn settled_file_reports_gone_if_it_never_existed() {
+        let missing = temp_path("never-existed");
+        assert_eq!(wait_for_settled_file(&missing), SettleOutcome::Gone);
+    }
+
+    #[test]
+    fn create_then_write_collapses_to_a_single_create() {
+        let path = PathBuf::from("/library/movie.mkv");
+        let mut pending = HashMap::new();
+
+        record_pending_event(&mut pending, DebouncedEvent::Create(path.clone()));
+        record_pending_event(&mut pending, DebouncedEvent::Write(path.clone()));
+        record_pending_event(&mut pending, DebouncedEvent::Write(path.clone()));
+
+        assert_eq!(pending.len(), 1);
+        assert!(matches!(pending.get(&path), Some(PendingEvent::Create)));
+    }
+
+    #[test]
+    fn create_then_remove_cancels_out() {
+        let path = PathBuf::from("/library/movie.mkv");
+        let mut pending = HashMap::new();
+
+        record_pending_event(&mut pending, DebouncedEvent::Create(path.clone()));
+        record_pending_event(&mut pending, DebouncedEvent::Remove(path.clone()));
+
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn remove_without_a_prior_create_is_recorded() {
+        let path = PathBuf::from("/library/movie.mkv");
+        let mut pending = HashMap::new();
+
+        record_pending_event(&mut pending, DebouncedEvent::Remove(path.clone()));
+
+        assert!(matches!(pending.get(&path), Some(PendingEvent::Remove)));
+    }
+
+    #[test]
+    fn rename_replaces_the_source_path_with_a_pending_rename_on_the_destination() {
+        let from = PathBuf::from("/library/old.mkv");
+        let to = PathBuf::from("/library/new.mkv");
+        let mut pending = HashMap::new();
+
+        record_pending_event(&mut pending, DebouncedEvent::Rename(from.clone(), to.clone()));
+
+        assert!(!pending.contains_key(&from));
+        assert!(matches!(
+            pending.get(&to),
+            Some(PendingEvent::Rename { from: f }) if *f == from
+        ));
+    }
+}